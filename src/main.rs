@@ -2,7 +2,7 @@ extern crate retry_command;
 extern crate getopts;
 
 use getopts::Fail::{ArgumentMissing};
-use retry_command::RetryCommand;
+use retry_command::{RetryCommand, LogFormat};
 use std::env;
 use std::io::{Write, stderr};
 use std::process::{Command, exit};
@@ -65,10 +65,26 @@ fn parse_args(args: &[String]) -> Result<(getopts::Options, getopts::Matches), g
     let mut opts = getopts::Options::new();
     opts.optopt("", "retry-timeout", "retry up to timeout seconds, then exit \
                                       with status 127", "TIMEOUT");
+    opts.optopt("", "retry-max", "run at most COUNT times, then exit with \
+                                  the last status", "COUNT");
+    opts.optopt("", "attempt-timeout", "kill and retry an attempt that runs \
+                                        longer than SECONDS", "SECONDS");
+    opts.optopt("", "retry-per-minute", "start at most COUNT attempts in any \
+                                         60 second window", "COUNT");
+    opts.optopt("", "retry-per-hour", "start at most COUNT attempts in any \
+                                       3600 second window", "COUNT");
     opts.optopt("", "retry-delay", "wait delay seconds between each retry", "DELAY");
+    opts.optopt("", "retry-backoff", "exponential backoff multiplier applied to \
+                                      retry-delay (default 1.0, which keeps it \
+                                      constant; 2.0 doubles the upper bound each \
+                                      time, and the actual delay is a random \
+                                      value below it)", "MULTIPLIER");
+    opts.optopt("", "retry-backoff-max", "cap the backoff delay at SECONDS", "SECONDS");
     opts.optmulti("", "retry-until", "retry until the exit code is one of the listed values (default 0)", "EXITCODE");
     opts.optmulti("", "retry-on", "retry if the exit code is one of the listed values", "EXITCODE");
     opts.optmulti("", "rewrite", "if the final exit status is a, change it to b; this happens after --retry-on/until processing", "<A>=<B>");
+    opts.optopt("", "log-format", "format attempts as 'human' (default) or \
+                                   'json'", "FORMAT");
     opts.optflag("h", "help", "display this help and exit");
     opts.optflag("v", "version", "output version information and exit");
     let matches = try!(opts.parse(args));
@@ -82,18 +98,50 @@ fn from_opts(matches: getopts::Matches) -> Result<RetryCommand, CliError> {
     let mut retry_cmd = RetryCommand::new(command);
     retry_cmd.logger(Box::new(stderr()));
 
+    if let Some(log_format) = matches.opt_str("log-format") {
+        retry_cmd.log_format(try!(parse(&log_format)));
+    }
+
     if let Some(retry_timeout) = matches.opt_str("retry-timeout") {
         retry_cmd.retry_timeout(Duration::from_secs(
             try!(parse(&retry_timeout))
         ));
     }
 
+    if let Some(retry_max) = matches.opt_str("retry-max") {
+        retry_cmd.retry_max(try!(parse(&retry_max)));
+    }
+
+    if let Some(attempt_timeout) = matches.opt_str("attempt-timeout") {
+        retry_cmd.attempt_timeout(Duration::from_secs(
+            try!(parse(&attempt_timeout))
+        ));
+    }
+
+    if let Some(retry_per_minute) = matches.opt_str("retry-per-minute") {
+        retry_cmd.retry_per_minute(try!(parse(&retry_per_minute)));
+    }
+
+    if let Some(retry_per_hour) = matches.opt_str("retry-per-hour") {
+        retry_cmd.retry_per_hour(try!(parse(&retry_per_hour)));
+    }
+
     if let Some(retry_delay) = matches.opt_str("retry-delay") {
         retry_cmd.retry_delay(Duration::from_secs(
             try!(parse(&retry_delay))
         ));
     }
 
+    if let Some(retry_backoff) = matches.opt_str("retry-backoff") {
+        retry_cmd.retry_backoff(try!(parse(&retry_backoff)));
+    }
+
+    if let Some(retry_backoff_max) = matches.opt_str("retry-backoff-max") {
+        retry_cmd.retry_backoff_max(Duration::from_secs(
+            try!(parse(&retry_backoff_max))
+        ));
+    }
+
     for retry_on in matches.opt_strs("retry-on") {
         retry_cmd.retry_on.push(try!(parse(&retry_on)));
     }
@@ -2,23 +2,59 @@ pub mod exit_code_ext;
 
 use exit_code_ext::ExitCodeExt;
 
+use std::collections::VecDeque;
 use std::io;
 use std::io::Write;
 use std::process::{Command, ExitStatus};
-use std::time::{Duration, Instant};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::thread::sleep;
 
+/// How each attempt is rendered to the logger.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// The original human-readable `{:?} {message}` output.
+    Human,
+    /// One JSON object per line, suitable for machine consumption.
+    Json
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<LogFormat, ()> {
+        match value {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(())
+        }
+    }
+}
+
 //#[derive(Debug)]
 pub struct RetryCommand {
     command: Command,
     retry_delay: Duration,
     retry_timeout: Duration,
+    retry_max: Option<u64>,
+    attempt_timeout: Option<Duration>,
+    retry_per_minute: Option<usize>,
+    retry_per_hour: Option<usize>,
+    retry_backoff: f64,
+    retry_backoff_max: Option<Duration>,
     retry_until: Vec<i32>,
     retry_on: Option<Vec<i32>>,
     rewrite: Vec<(i32, i32)>,
+    log_format: LogFormat,
+    jitter_state: u64,
     logger: Option<Box<Write>>
 }
 
+/// Ceiling applied to the exponential backoff delay when the caller does not
+/// set `retry_backoff_max`, so an unbounded `backoff^n` can't overflow to
+/// `inf` (and then to an effectively infinite sleep).
+const DEFAULT_BACKOFF_MAX_SECS: f64 = 3600.0;
+
 /// Builder for running a `Command` repeatedly until a desired state or timeout
 /// is reached.
 impl RetryCommand {
@@ -28,10 +64,18 @@ impl RetryCommand {
         RetryCommand {
             command: command,
             retry_timeout: Duration::from_secs(0),
+            retry_max: None,
+            attempt_timeout: None,
+            retry_per_minute: None,
+            retry_per_hour: None,
+            retry_backoff: 1.0,
+            retry_backoff_max: None,
             retry_until: vec![0],
             retry_on: None,
             retry_delay: Duration::from_secs(0),
             rewrite: vec![],
+            log_format: LogFormat::Human,
+            jitter_state: seed_jitter(),
             logger: None
         }
     }
@@ -44,12 +88,61 @@ impl RetryCommand {
         self
     }
 
+    /// Stop retrying once the command has been run this many times, even if
+    /// `retry_timeout` has not elapsed. When the cap is reached without
+    /// hitting a `retry_until` code, the final exit code is returned (through
+    /// `rewrite`) exactly as a timeout would be.
+    pub fn retry_max(&mut self, value: u64) -> &mut Self {
+        self.retry_max = Some(value);
+        self
+    }
+
+    /// Kill an attempt that runs longer than this `Duration`. The killed
+    /// attempt is reaped and treated like any other failing exit code, so it
+    /// feeds back into `should_stop` and the retry delay. Without this a hung
+    /// child would block forever and `retry_timeout` could never fire.
+    pub fn attempt_timeout(&mut self, value: Duration) -> &mut Self {
+        self.attempt_timeout = Some(value);
+        self
+    }
+
+    /// Cap the number of attempts started in any sliding 60 second window.
+    /// This throttles a fast-failing command and is applied in addition to
+    /// `retry_delay`.
+    pub fn retry_per_minute(&mut self, value: usize) -> &mut Self {
+        self.retry_per_minute = Some(value);
+        self
+    }
+
+    /// Cap the number of attempts started in any sliding 3600 second window.
+    /// Like `retry_per_minute`, this is applied on top of `retry_delay`.
+    pub fn retry_per_hour(&mut self, value: usize) -> &mut Self {
+        self.retry_per_hour = Some(value);
+        self
+    }
+
     /// When a `Command` will be retried, sleep this `Duration` first.
     pub fn retry_delay(&mut self, value: Duration) -> &mut Self {
         self.retry_delay = value;
         self
     }
 
+    /// Multiplier for exponential backoff. The delay before the *n*th retry
+    /// becomes `min(retry_delay * backoff^n, retry_backoff_max)` with full
+    /// jitter applied. The default of `1.0`, or any value below it, keeps
+    /// `retry_delay` constant.
+    pub fn retry_backoff(&mut self, value: f64) -> &mut Self {
+        self.retry_backoff = value;
+        self
+    }
+
+    /// Upper bound on the backoff delay. Without it the delay is capped at
+    /// `DEFAULT_BACKOFF_MAX_SECS` (one hour).
+    pub fn retry_backoff_max(&mut self, value: Duration) -> &mut Self {
+        self.retry_backoff_max = Some(value);
+        self
+    }
+
     /// Vec of exit codes which represent a desired exit code.
     /// Default to `[0]`.
     pub fn retry_until(&mut self, value: Vec<i32>) -> &mut Self {
@@ -70,6 +163,13 @@ impl RetryCommand {
         self
     }
 
+    /// Select how attempts are formatted for the logger. Defaults to
+    /// `LogFormat::Human`.
+    pub fn log_format(&mut self, value: LogFormat) -> &mut Self {
+        self.log_format = value;
+        self
+    }
+
     /// When provided, log messages will be written to this object.
     pub fn logger(&mut self, value: Box<Write>) -> &mut Self {
         self.logger = Some(value);
@@ -90,27 +190,144 @@ impl RetryCommand {
 
     fn status_and_code(&mut self) -> io::Result<(io::Result<ExitStatus>, i32)> {
         let start = Instant::now();
+        let mut attempts = 0u64;
+        let mut attempt_times: VecDeque<Instant> = VecDeque::new();
 
         loop {
-            let result = self.command.status();
+            self.throttle(&mut attempt_times);
+            let attempt_start = Instant::now();
+            let result = self.run_once();
+            attempts += 1;
+            let elapsed = Instant::now() - attempt_start;
 
             let (code, msg_opt) = try!(result.exit_code());
+            let stop = self.should_stop(code, start, attempts);
 
-            if let Some(msg) = msg_opt {
-                self.log(msg);
-            }
+            self.log(attempts, code, elapsed, !stop, msg_opt);
 
-            if self.should_stop(code, start) {
+            if stop {
                 return Ok((result, self.rewrite_code(code)));
             } else {
-                sleep(self.retry_delay);
+                sleep(self.backoff_delay(attempts));
             }
         }
     }
 
-    fn log(&mut self, msg: String) {
-        if let Some(ref mut io) = self.logger {
-            writeln!(io, "{:?} {}", self.command, msg).unwrap_or(());
+    /// Delay to wait before the next retry. With a multiplier of `1.0` (the
+    /// default) or less this is just `retry_delay`; otherwise it grows exponentially,
+    /// capped at `retry_backoff_max` (or `DEFAULT_BACKOFF_MAX_SECS` when
+    /// unset), and is then reduced by full jitter to a uniformly random value
+    /// in `[0, delay]` so concurrent retries don't stay synchronized.
+    fn backoff_delay(&mut self, attempts: u64) -> Duration {
+        if self.retry_backoff <= 1.0 {
+            return self.retry_delay;
+        }
+        let ceiling = match self.retry_backoff_max {
+            Some(max) => secs_f64(max),
+            None => DEFAULT_BACKOFF_MAX_SECS
+        };
+        let mut delay = secs_f64(self.retry_delay) * self.retry_backoff.powi(attempts as i32);
+        // `!(delay <= ceiling)` also clamps an overflowed `inf` (and any NaN).
+        if !(delay <= ceiling) {
+            delay = ceiling;
+        }
+        dur_secs_f64(delay * self.next_jitter())
+    }
+
+    /// A uniformly distributed fraction in `[0, 1)` from a per-process
+    /// xorshift generator, used to apply full jitter without a dependency.
+    fn next_jitter(&mut self) -> f64 {
+        let mut x = self.jitter_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.jitter_state = x;
+        // Top 53 bits map exactly onto an f64 mantissa.
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Enforce the per-minute and per-hour rate limits before an attempt.
+    /// Old timestamps are pruned, and if either window is already full we
+    /// sleep until its oldest in-window attempt expires. The new attempt's
+    /// start time is recorded on the way out.
+    fn throttle(&self, times: &mut VecDeque<Instant>) {
+        let hour = Duration::from_secs(3600);
+        while let Some(&front) = times.front() {
+            if Instant::now() - front >= hour {
+                times.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.wait_for_window(times, Duration::from_secs(60), self.retry_per_minute);
+        self.wait_for_window(times, hour, self.retry_per_hour);
+        times.push_back(Instant::now());
+    }
+
+    /// Block until fewer than `limit` of the recorded timestamps fall within
+    /// `window`, sleeping until the oldest in-window timestamp expires.
+    fn wait_for_window(&self, times: &VecDeque<Instant>, window: Duration, limit: Option<usize>) {
+        let limit = match limit {
+            Some(limit) => limit,
+            None => return
+        };
+        loop {
+            let now = Instant::now();
+            let count = times.iter().filter(|&&t| now - t < window).count();
+            if count < limit {
+                return;
+            }
+            match times.iter().find(|&&t| now - t < window) {
+                Some(&oldest) => sleep((oldest + window) - now),
+                None => return
+            }
+        }
+    }
+
+    /// Run the command once, honoring `attempt_timeout`. Without a timeout
+    /// this is just `Command::status`; with one the child is spawned and
+    /// polled, then killed and reaped if it overruns. A killed child's
+    /// `ExitStatus` carries its signal, so it resolves to a retryable exit
+    /// code through `ExitCodeExt` like any other failure.
+    fn run_once(&mut self) -> io::Result<ExitStatus> {
+        match self.attempt_timeout {
+            None => self.command.status(),
+            Some(timeout) => {
+                let mut child = try!(self.command.spawn());
+                let start = Instant::now();
+                let poll = Duration::from_millis(50);
+                loop {
+                    if let Some(status) = try!(child.try_wait()) {
+                        return Ok(status);
+                    }
+                    if (Instant::now() - start) >= timeout {
+                        try!(child.kill());
+                        return child.wait();
+                    }
+                    sleep(poll);
+                }
+            }
+        }
+    }
+
+    /// Write one line describing a finished attempt. The human format keeps
+    /// the original behavior of only emitting when there is a message; the
+    /// JSON format emits one object per attempt.
+    fn log(&mut self, number: u64, code: i32, elapsed: Duration, retried: bool, msg_opt: Option<String>) {
+        match self.log_format {
+            LogFormat::Human => {
+                if let Some(msg) = msg_opt {
+                    if let Some(ref mut io) = self.logger {
+                        writeln!(io, "{:?} {}", self.command, msg).unwrap_or(());
+                    }
+                }
+            },
+            LogFormat::Json => {
+                let line = json_attempt(&self.command, number, code, elapsed, retried);
+                if let Some(ref mut io) = self.logger {
+                    writeln!(io, "{}", line).unwrap_or(());
+                }
+            }
         }
     }
 
@@ -124,7 +341,7 @@ impl RetryCommand {
         return code;
     }
 
-    fn should_stop(&self, code: i32, start: Instant) -> bool {
+    fn should_stop(&self, code: i32, start: Instant, attempts: u64) -> bool {
         if self.retry_until.contains(&code) {
             return true;
         }
@@ -132,6 +349,97 @@ impl RetryCommand {
             Some(ref retry_on) => !retry_on.contains(&code),
             None => false
         };
-        (ret || ((Instant::now() - start) >= self.retry_timeout))
+        let capped = match self.retry_max {
+            Some(retry_max) => attempts >= retry_max,
+            None => false
+        };
+        (ret || capped || ((Instant::now() - start) >= self.retry_timeout))
+    }
+}
+
+/// Seed the per-process jitter generator from the wall clock and pid, mixed
+/// so that `retry` processes started in lockstep still diverge. Never returns
+/// zero, which xorshift cannot escape.
+fn seed_jitter() -> u64 {
+    let nanos = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(dur) => ((dur.as_secs() as u64) << 20) ^ (dur.subsec_nanos() as u64),
+        Err(_) => 0
+    };
+    let mixed = nanos ^ (std::process::id() as u64).wrapping_mul(0x9E3779B97F4A7C15);
+    if mixed == 0 { 0x9E3779B97F4A7C15 } else { mixed }
+}
+
+/// Render one attempt as a single-line JSON object.
+fn json_attempt(command: &Command, number: u64, code: i32, elapsed: Duration, retried: bool) -> String {
+    format!(
+        "{{\"command\":\"{}\",\"attempt\":{},\"code\":{},\"elapsed\":{},\"timestamp\":\"{}\",\"retried\":{}}}",
+        json_escape(&format!("{:?}", command)),
+        number,
+        code,
+        secs_f64(elapsed),
+        iso8601_now(),
+        retried
+    )
+}
+
+/// Escape a string for inclusion in a JSON string literal.
+fn json_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+/// The current UTC time as an ISO-8601 `YYYY-MM-DDThh:mm:ssZ` string.
+fn iso8601_now() -> String {
+    let secs = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur.as_secs() as i64,
+        Err(_) => 0
+    };
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, rem / 3600, (rem % 3600) / 60, rem % 60
+    )
+}
+
+/// Convert a count of days since the Unix epoch into a civil `(year, month,
+/// day)` in the proleptic Gregorian calendar (Howard Hinnant's algorithm).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let year = yoe + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+/// A `Duration` as fractional seconds.
+fn secs_f64(value: Duration) -> f64 {
+    value.as_secs() as f64 + value.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+/// Fractional seconds back into a `Duration`, clamping negatives to zero.
+fn dur_secs_f64(value: f64) -> Duration {
+    if value <= 0.0 {
+        return Duration::from_secs(0);
     }
+    let whole = value.floor();
+    let nanos = ((value - whole) * 1_000_000_000.0) as u32;
+    Duration::new(whole as u64, nanos)
 }